@@ -6,10 +6,78 @@ use consensus_types::{
     block::Block,
     common::{Author, Payload, Round},
 };
+use crypto::HashValue;
 use logger::prelude::*;
 use siphasher::sip::SipHasher24;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 use std::hash::{Hash, Hasher};
 
+/// Number of trailing rounds for which observed proposals are retained for equivocation
+/// detection; entries for rounds below `highest_processed_round - EQUIVOCATION_WINDOW` are
+/// pruned to bound memory.
+const EQUIVOCATION_WINDOW: Round = 10;
+
+/// Number of rounds of computed candidate lists to keep cached: large enough to cover the
+/// current round and a few neighbors.
+const CANDIDATE_CACHE_SIZE: usize = 16;
+
+/// Target total number of seats `compute_seat_price` allocates across the voting power table.
+/// Bounds the size of the expanded, stake-replicated `proposers` pool independent of how wide
+/// the stake distribution is.
+const TOTAL_SEAT_BUDGET: u64 = 1_000;
+
+/// Fixed-capacity, round-keyed LRU cache: evicts the least-recently-used round once
+/// `capacity` is exceeded. Hand-rolled rather than pulling in an external crate, since this
+/// is the only piece of code that needs it.
+struct RoundCandidateCache {
+    capacity: usize,
+    entries: HashMap<Round, Vec<Author>>,
+    // Back is most recently used, front is least recently used.
+    recency: VecDeque<Round>,
+}
+
+impl RoundCandidateCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recency: VecDeque::new(),
+        }
+    }
+
+    fn get(&mut self, round: Round) -> Option<Vec<Author>> {
+        let candidates = self.entries.get(&round).cloned()?;
+        self.touch(round);
+        Some(candidates)
+    }
+
+    fn put(&mut self, round: Round, candidates: Vec<Author>) {
+        if !self.entries.contains_key(&round) && self.entries.len() >= self.capacity {
+            if let Some(oldest) = self.recency.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(round, candidates);
+        self.touch(round);
+    }
+
+    fn touch(&mut self, round: Round) {
+        self.recency.retain(|r| *r != round);
+        self.recency.push_back(round);
+    }
+}
+
+/// Signals that `author` proposed two different blocks for the same `round`, for the caller
+/// to route to the slashing layer.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ProposerEquivocation {
+    pub author: Author,
+    pub round: Round,
+    pub first: HashValue,
+    pub second: HashValue,
+}
+
 // A deterministic hashing function based on SipHash 2-4 hasher
 pub fn hash(val: u64) -> u64 {
     let mut hasher = SipHasher24::new();
@@ -17,14 +85,35 @@ pub fn hash(val: u64) -> u64 {
     hasher.finish()
 }
 
+/// Derives an index in `0..bound` from `cur_val`, re-hashing `cur_val` in place on every
+/// draw (including rejected ones). Uses bounded-integer rejection sampling instead of a plain
+/// modulo, so the result is exactly uniform over `0..bound` regardless of whether `bound`
+/// divides 2^64. This routine is pinned: it must never change, or upgrades would silently
+/// alter the elected leader sequence.
+fn bounded_index(cur_val: &mut u64, bound: u64) -> usize {
+    let zone = (bound << bound.leading_zeros()).wrapping_sub(1);
+    loop {
+        *cur_val = hash(*cur_val);
+        let mul = u128::from(*cur_val) * u128::from(bound);
+        let hi = (mul >> 64) as u64;
+        let lo = mul as u64;
+        if lo <= zone {
+            return hi as usize;
+        }
+    }
+}
+
 /// The MultiProposer maps a round to an ordered list of authors.
-/// The primary proposer is determined by an index of hash(round) % num_proposers.
-/// The secondary proposer is determined by hash(hash(round)) % (num_proposers - 1), etc.
+/// The primary proposer is determined by an unbiased bounded index drawn from the hash chain
+/// hash(round), hash(hash(round)), ... (a rejected draw moves on to the next hash).
+/// The secondary proposer is drawn the same way, continuing the chain where the primary's
+/// draw stopped, etc.
 /// In order to ensure the required number of proposers, a set of the proposers to choose from
 /// is updated after each hash: a chosen candidate is removed to avoid duplication.
 ///
 /// Note the hash doesn't have to be cryptographic. The goal is to make sure that different
-/// combinations of consecutive leaders are going to appear with equal probability.
+/// combinations of consecutive leaders are going to appear with exactly equal probability,
+/// which is why candidate indices are drawn via rejection sampling rather than a plain modulo.
 
 /// While each round has more than a single valid proposer, only the primary proposer is
 /// considered for `process_proposal`. The best backup proposer is returned in
@@ -41,6 +130,18 @@ pub struct MultiProposer<T> {
     backup_proposal_round: Round,
     // The proposal is kept in a tuple (rank, block)
     backup_proposal: Option<(usize, Block<T>)>,
+    // The first block hash seen from each (round, author), used to detect equivocation.
+    // Pruned down to a small trailing window of rounds as `highest_processed_round` advances.
+    observed_proposals: HashMap<(Round, Author), HashValue>,
+    // Highest round seen by `process_proposal` so far, used to bound `observed_proposals`.
+    highest_processed_round: Round,
+    // Equivocations detected by `process_proposal`, queued in detection order until drained
+    // by `take_equivocations` so that a burst of detections between polls is never lost.
+    pending_equivocations: Vec<ProposerEquivocation>,
+    // Caches the computed candidate list per round. `RefCell` because `get_candidates` is
+    // called from `&self` methods (`is_valid_proposer`, `get_valid_proposers`) but still needs
+    // to populate the cache.
+    candidate_cache: RefCell<RoundCandidateCache>,
 }
 
 impl<T> MultiProposer<T> {
@@ -62,20 +163,198 @@ impl<T> MultiProposer<T> {
             num_proposers_per_round,
             backup_proposal_round: 0,
             backup_proposal: None,
+            observed_proposals: HashMap::new(),
+            highest_processed_round: 0,
+            pending_equivocations: Vec::new(),
+            candidate_cache: RefCell::new(RoundCandidateCache::new(CANDIDATE_CACHE_SIZE)),
+        }
+    }
+
+    /// Creates a `MultiProposer` whose per-round candidate selection is weighted by stake:
+    /// each author is replicated `floor(voting_power / seat_price)` times in the underlying
+    /// proposer pool, so the probability of an author being drawn as primary (or backup)
+    /// proposer is proportional to its stake while remaining fully deterministic across all
+    /// honest replicas that agree on the voting power table. `seat_price` is computed
+    /// internally (not supplied by the caller) via `compute_seat_price`, which bounds the
+    /// total number of seats (and therefore the size of the expanded `proposers` pool)
+    /// regardless of how skewed the stake distribution is.
+    pub fn new_weighted(voting_power: Vec<(Author, u64)>, num_proposers_per_round: usize) -> Self {
+        assert!(
+            voting_power.len() >= num_proposers_per_round,
+            "num_proposers_per_round = {}, while there are only {} distinct authors with voting power",
+            num_proposers_per_round,
+            voting_power.len()
+        );
+
+        let seat_price = Self::compute_seat_price(&voting_power);
+        let mut proposers = vec![];
+        for &(author, power) in &voting_power {
+            for _ in 0..(power / seat_price) {
+                proposers.push(author);
+            }
         }
+
+        let eligible_authors = voting_power
+            .iter()
+            .filter(|(_, power)| *power >= seat_price)
+            .count();
+        assert!(
+            eligible_authors >= num_proposers_per_round,
+            "seat price {} leaves only {} of {} authors with a seat, need {}",
+            seat_price,
+            eligible_authors,
+            voting_power.len(),
+            num_proposers_per_round
+        );
+
+        Self::new(proposers, num_proposers_per_round)
     }
 
+    /// Computes the largest seat price such that the total number of seats allocated across
+    /// `voting_power` (`sum(floor(power_i / seat_price))`) is still at least
+    /// `TOTAL_SEAT_BUDGET`, following NEAR's binary-search seat-price construction. This caps
+    /// the size of the expanded `proposers` pool to roughly `TOTAL_SEAT_BUDGET` regardless of
+    /// how skewed the stake distribution is (e.g. a whale author next to a minimum-stake one),
+    /// instead of growing unboundedly with the stake ratio. When the total stake is below
+    /// `TOTAL_SEAT_BUDGET`, no price reaches the budget and the result is 1 (one seat per unit
+    /// of stake). Panics if `voting_power` is empty or every author holds zero power.
+    fn compute_seat_price(voting_power: &[(Author, u64)]) -> u64 {
+        let max_power = voting_power
+            .iter()
+            .map(|(_, power)| *power)
+            .max()
+            .expect("voting power table must not be empty");
+        assert!(max_power > 0, "voting power table has no stake");
+
+        let total_seats_at = |price: u64| -> u64 {
+            voting_power.iter().map(|(_, power)| power / price).sum()
+        };
+
+        let (mut lo, mut hi) = (1u64, max_power);
+        while lo < hi {
+            let mid = lo + (hi - lo + 1) / 2;
+            if total_seats_at(mid) >= TOTAL_SEAT_BUDGET {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+        lo
+    }
+
+    /// Returns the ordered candidate list for `round`, serving it out of `candidate_cache`
+    /// when available. Transparent: always returns the same result as recomputing from
+    /// scratch, just without repeating the per-round hashing on a cache hit.
     fn get_candidates(&self, round: Round) -> Vec<Author> {
+        if let Some(cached) = self.candidate_cache.borrow_mut().get(round) {
+            return cached;
+        }
+        let candidates = self.compute_candidates(round);
+        self.candidate_cache
+            .borrow_mut()
+            .put(round, candidates.clone());
+        candidates
+    }
+
+    /// Precomputes and caches the candidate list for `round`, so that it can later be served
+    /// out of `candidate_cache` in O(1) off the consensus hot path.
+    pub fn warm_cache(&self, round: Round) {
+        self.get_candidates(round);
+    }
+
+    fn compute_candidates(&self, round: Round) -> Vec<Author> {
         let mut res = vec![];
         let mut candidates = self.proposers.clone();
         let mut cur_val = round;
-        for _ in 0..self.num_proposers_per_round {
-            cur_val = hash(cur_val);
-            let idx = (cur_val % candidates.len() as u64) as usize;
-            res.push(candidates.swap_remove(idx));
+        while res.len() < self.num_proposers_per_round && !candidates.is_empty() {
+            let idx = bounded_index(&mut cur_val, candidates.len() as u64);
+            let candidate = candidates.swap_remove(idx);
+            // The underlying pool may contain an author multiple times (stake-weighted
+            // replication), so skip a candidate already picked this round instead of
+            // returning it twice.
+            if !res.contains(&candidate) {
+                res.push(candidate);
+            }
         }
+        assert_eq!(
+            res.len(),
+            self.num_proposers_per_round,
+            "round {} yielded only {} distinct candidates out of {} proposers, need {}",
+            round,
+            res.len(),
+            self.proposers.len(),
+            self.num_proposers_per_round
+        );
         res
     }
+
+    /// Records that `author` proposed `block_hash` for `round`, returning a
+    /// `ProposerEquivocation` if a different block hash was already observed for the same
+    /// (round, author) pair. Also advances `highest_processed_round` and prunes observed
+    /// entries that fall outside `EQUIVOCATION_WINDOW`.
+    fn record_proposal(
+        &mut self,
+        round: Round,
+        author: Author,
+        block_hash: HashValue,
+    ) -> Option<ProposerEquivocation> {
+        if round > self.highest_processed_round {
+            self.highest_processed_round = round;
+            let low_water_mark = self
+                .highest_processed_round
+                .saturating_sub(EQUIVOCATION_WINDOW);
+            self.observed_proposals
+                .retain(|(r, _), _| *r >= low_water_mark);
+        }
+
+        match self.observed_proposals.entry((round, author)) {
+            std::collections::hash_map::Entry::Occupied(entry) => {
+                let first = *entry.get();
+                if first != block_hash {
+                    return Some(ProposerEquivocation {
+                        author,
+                        round,
+                        first,
+                        second: block_hash,
+                    });
+                }
+                None
+            }
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(block_hash);
+                None
+            }
+        }
+    }
+
+    /// Drains and returns all equivocations detected so far, in detection order, for the
+    /// caller to route to the slashing layer. Unlike a single-slot `Option`, this never loses
+    /// an earlier detection to a later one if the caller polls less often than
+    /// `process_proposal` is called.
+    pub fn take_equivocations(&mut self) -> Vec<ProposerEquivocation> {
+        std::mem::take(&mut self.pending_equivocations)
+    }
+
+    /// Returns the ordered candidate list (primary first, then backups by rank) for each of
+    /// the `count` rounds starting at `start`. Selection is a pure function of round and the
+    /// proposer set, so this works for any past or future round. Computes directly rather than
+    /// going through `candidate_cache`: a query over a wide round window would otherwise evict
+    /// the entries the hot path (`is_valid_proposer`, `process_proposal`) relies on. Shared by
+    /// the `get_proposer_schedule` inherent and `ProposerElection::get_proposer_schedule` trait
+    /// methods below.
+    fn schedule(&self, start: Round, count: usize) -> Vec<(Round, Vec<Author>)> {
+        (0..count as Round)
+            .map(|offset| {
+                let round = start + offset;
+                (round, self.compute_candidates(round))
+            })
+            .collect()
+    }
+
+    /// See `ProposerElection::get_proposer_schedule`.
+    pub fn get_proposer_schedule(&self, start: Round, count: usize) -> Vec<(Round, Vec<Author>)> {
+        self.schedule(start, count)
+    }
 }
 
 impl<T: Payload> ProposerElection<T> for MultiProposer<T> {
@@ -94,6 +373,13 @@ impl<T: Payload> ProposerElection<T> for MultiProposer<T> {
     fn process_proposal(&mut self, proposal: Block<T>) -> Option<Block<T>> {
         let author = proposal.author()?;
         let round = proposal.round();
+        if let Some(equivocation) = self.record_proposal(round, author, proposal.id()) {
+            warn!(
+                "Detected equivocation: author {} proposed both {} and {} for round {}",
+                equivocation.author, equivocation.first, equivocation.second, round
+            );
+            self.pending_equivocations.push(equivocation);
+        }
         let candidates = self.get_candidates(round);
         for (rank, candidate) in candidates.iter().enumerate() {
             if rank == 0 && author == *candidate {
@@ -143,4 +429,114 @@ impl<T: Payload> ProposerElection<T> for MultiProposer<T> {
 
         None
     }
+
+    fn get_proposer_schedule(&self, start: Round, count: usize) -> Vec<(Round, Vec<Author>)> {
+        self.schedule(start, count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use consensus_types::common::Author;
+
+    #[test]
+    fn new_weighted_gives_every_author_a_seat() {
+        let authors: Vec<Author> = (0..4).map(|_| Author::random()).collect();
+        let voting_power = vec![
+            (authors[0], 100),
+            (authors[1], 50),
+            (authors[2], 10),
+            (authors[3], 1),
+        ];
+        let proposer: MultiProposer<()> = MultiProposer::new_weighted(voting_power, 4);
+        for round in 0..20 {
+            let candidates = proposer.get_candidates(round);
+            assert_eq!(candidates.len(), 4);
+            for author in &authors {
+                assert!(candidates.contains(author));
+            }
+        }
+    }
+
+    #[test]
+    fn compute_seat_price_bounds_total_seats_for_skewed_stake() {
+        let authors: Vec<Author> = (0..2).map(|_| Author::random()).collect();
+        // A 10^9-to-1 stake ratio: a naive "seat price = min stake" choice would replicate
+        // the whale author roughly a billion times.
+        let voting_power = vec![(authors[0], 1_000_000_000), (authors[1], 1)];
+        let seat_price = MultiProposer::<()>::compute_seat_price(&voting_power);
+        let total_seats: u64 = voting_power.iter().map(|(_, power)| power / seat_price).sum();
+        assert!(total_seats < TOTAL_SEAT_BUDGET * 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "distinct authors with voting power")]
+    fn new_weighted_rejects_too_few_distinct_authors() {
+        let authors: Vec<Author> = (0..2).map(|_| Author::random()).collect();
+        let voting_power = vec![(authors[0], 100), (authors[1], 1)];
+        let _: MultiProposer<()> = MultiProposer::new_weighted(voting_power, 3);
+    }
+
+    #[test]
+    fn bounded_index_stays_in_range() {
+        for bound in 1..257u64 {
+            let mut cur_val = 42u64;
+            for _ in 0..100 {
+                assert!(bounded_index(&mut cur_val, bound) < bound as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn take_equivocations_drains_every_detection_since_last_poll() {
+        let authors: Vec<Author> = (0..2).map(|_| Author::random()).collect();
+        let mut proposer: MultiProposer<()> = MultiProposer::new(authors.clone(), 2);
+
+        // Two distinct equivocations land before the caller ever polls.
+        let first = proposer.record_proposal(1, authors[0], HashValue::random());
+        assert!(first.is_none());
+        let second = proposer.record_proposal(1, authors[0], HashValue::random());
+        assert!(second.is_some());
+        proposer.pending_equivocations.push(second.unwrap());
+
+        let third = proposer.record_proposal(2, authors[1], HashValue::random());
+        assert!(third.is_none());
+        let fourth = proposer.record_proposal(2, authors[1], HashValue::random());
+        assert!(fourth.is_some());
+        proposer.pending_equivocations.push(fourth.unwrap());
+
+        let drained = proposer.take_equivocations();
+        assert_eq!(drained.len(), 2);
+        assert!(proposer.take_equivocations().is_empty());
+    }
+
+    #[test]
+    fn round_candidate_cache_is_transparent_and_evicts_lru() {
+        let mut cache = RoundCandidateCache::new(2);
+        let author = Author::random();
+        assert!(cache.get(1).is_none());
+        cache.put(1, vec![author]);
+        assert_eq!(cache.get(1), Some(vec![author]));
+
+        cache.put(2, vec![author]);
+        // Touching round 1 again makes round 2 the least recently used.
+        assert_eq!(cache.get(1), Some(vec![author]));
+        cache.put(3, vec![author]);
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(1), Some(vec![author]));
+        assert_eq!(cache.get(3), Some(vec![author]));
+    }
+
+    #[test]
+    fn proposer_schedule_matches_per_round_lookups() {
+        let authors: Vec<Author> = (0..5).map(|_| Author::random()).collect();
+        let proposer: MultiProposer<()> = MultiProposer::new(authors, 3);
+
+        let schedule = proposer.get_proposer_schedule(10, 5);
+        assert_eq!(schedule.len(), 5);
+        for (round, candidates) in schedule {
+            assert_eq!(candidates, proposer.get_candidates(round));
+        }
+    }
 }
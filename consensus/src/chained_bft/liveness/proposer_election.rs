@@ -0,0 +1,33 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+use consensus_types::{
+    block::Block,
+    common::{Author, Round},
+};
+
+/// ProposerElection incorporates the logic of choosing a leader among a list of candidates.
+/// We are open to a possibility for having multiple proposers per round, the ultimate choice
+/// of a proposal is exposed by the election protocol via the stream of proposals.
+pub trait ProposerElection<T> {
+    // Return the valid proposer for a round (this might be ambiguous in case we have multiple
+    // proposers per round).
+    fn is_valid_proposer(&self, author: Author, round: Round) -> Option<Author>;
+
+    // Return all the possible valid proposers for a given round (this is used by the pacemaker
+    // and proposal generator)
+    fn get_valid_proposers(&self, round: Round) -> Vec<Author>;
+
+    // Process the proposal that is sent by a proposer.
+    fn process_proposal(&mut self, proposal: Block<T>) -> Option<Block<T>>;
+
+    // In case the consensus is not able to retrieve the content of the proposal from the
+    // primary proposer, it is able to ask for a backup, less efficient proposal from the best
+    // secondary proposer.
+    fn take_backup_proposal(&mut self, round: Round) -> Option<Block<T>>;
+
+    // Return the ordered candidate list for each of the `count` rounds starting at `start`,
+    // e.g. for monitoring, liveness forecasting, or historical auditing of "who proposes over
+    // the next N rounds" without probing `get_valid_proposers` one round at a time.
+    fn get_proposer_schedule(&self, start: Round, count: usize) -> Vec<(Round, Vec<Author>)>;
+}